@@ -0,0 +1,384 @@
+//! A codec bridging a raw byte stream to a `Stream`/`Sink` of `Message`s,
+//! for use with tokio's `Framed` transport.
+
+use std::io;
+use std::io::Write;
+use std::mem;
+
+use bytes::{BufMut, BytesMut};
+use byteorder::{BigEndian, ByteOrder};
+use rand;
+use rand::Rng;
+use tokio_util::codec::{Decoder, Encoder};
+
+use dataframe::Opcode;
+use message::{Message, Utf8Validator, DEFAULT_MAX_MESSAGE_SIZE};
+use result::{WebSocketError, WebSocketResult};
+use ws::dataframe::DataFrame;
+use ws::Message as _;
+
+/// A single data frame read off the wire, kept around only long enough to
+/// be handed to `Message::from_dataframes_with_limit` once a full message
+/// has been collected.
+struct Frame {
+	fin: bool,
+	reserved: [bool; 3],
+	opcode: Opcode,
+	payload: Vec<u8>,
+}
+
+impl DataFrame for Frame {
+	fn is_last(&self) -> bool {
+		self.fin
+	}
+
+	fn opcode(&self) -> Opcode {
+		self.opcode
+	}
+
+	fn reserved<'b>(&'b self) -> &'b [bool; 3] {
+		&self.reserved
+	}
+
+	fn payload<'b>(&'b self) -> &'b [u8] {
+		&self.payload
+	}
+
+	fn write_payload<W>(&self, socket: &mut W) -> io::Result<()>
+	where W: Write {
+		socket.write_all(&self.payload)
+	}
+}
+
+fn opcode_from_nibble(nibble: u8) -> WebSocketResult<Opcode> {
+	match nibble {
+		0x0 => Ok(Opcode::Continuation),
+		0x1 => Ok(Opcode::Text),
+		0x2 => Ok(Opcode::Binary),
+		0x8 => Ok(Opcode::Close),
+		0x9 => Ok(Opcode::Ping),
+		0xA => Ok(Opcode::Pong),
+		other => Err(WebSocketError::ProtocolError(format!("Unknown opcode: {}", other))),
+	}
+}
+
+fn nibble_from_opcode(opcode: Opcode) -> WebSocketResult<u8> {
+	match opcode {
+		Opcode::Continuation => Ok(0x0),
+		Opcode::Text => Ok(0x1),
+		Opcode::Binary => Ok(0x2),
+		Opcode::Close => Ok(0x8),
+		Opcode::Ping => Ok(0x9),
+		Opcode::Pong => Ok(0xA),
+		_ => Err(WebSocketError::ProtocolError("Cannot encode this opcode".to_string())),
+	}
+}
+
+/// A masking key drawn from a CSPRNG, as required by
+/// [RFC 6455 §5.3](https://tools.ietf.org/html/rfc6455#section-5.3): it must
+/// not be predictable by a peer, since a predictable key defeats the
+/// protection masking gives proxies that cache based on frame content.
+fn generate_mask_key() -> [u8; 4] {
+	rand::thread_rng().gen()
+}
+
+fn write_frame<D: DataFrame>(frame: &D, mask: bool, dst: &mut BytesMut) -> WebSocketResult<()> {
+	let mut payload = Vec::new();
+	try!(frame.write_payload(&mut payload));
+
+	let mut first_byte = try!(nibble_from_opcode(frame.opcode()));
+	if frame.is_last() {
+		first_byte |= 0x80;
+	}
+	let reserved = frame.reserved();
+	if reserved[0] { first_byte |= 0x40; }
+	if reserved[1] { first_byte |= 0x20; }
+	if reserved[2] { first_byte |= 0x10; }
+	dst.put_u8(first_byte);
+
+	let mask_bit = if mask { 0x80 } else { 0x00 };
+	let len = payload.len();
+	if len < 126 {
+		dst.put_u8(mask_bit | len as u8);
+	} else if len <= u16::max_value() as usize {
+		dst.put_u8(mask_bit | 126);
+		dst.put_u16(len as u16);
+	} else {
+		dst.put_u8(mask_bit | 127);
+		dst.put_u64(len as u64);
+	}
+
+	if mask {
+		let key = generate_mask_key();
+		dst.extend_from_slice(&key);
+		for (i, byte) in payload.iter_mut().enumerate() {
+			*byte ^= key[i % 4];
+		}
+	}
+	dst.extend_from_slice(&payload);
+
+	Ok(())
+}
+
+/// Bridges a byte stream to a `Stream`/`Sink` of `Message`, so that
+/// `Message` can be dropped directly into a `Framed` transport instead of
+/// hand-rolling the read/reassemble loop.
+///
+/// Continuation frames are buffered internally via a fragment collector
+/// until a complete message is available.
+pub struct MessageCodec {
+	is_server: bool,
+	max_size: usize,
+	fragments: Vec<Frame>,
+	/// Validates a `Text` message's fragments as they arrive, so that
+	/// invalid UTF-8 is rejected as soon as the offending fragment is
+	/// decoded instead of waiting for the whole message to reassemble.
+	text_validator: Option<Utf8Validator>,
+}
+
+impl MessageCodec {
+	/// Creates a client-mode codec with the default message size limit.
+	pub fn new() -> Self {
+		MessageCodec {
+			is_server: false,
+			max_size: DEFAULT_MAX_MESSAGE_SIZE,
+			fragments: Vec::new(),
+			text_validator: None,
+		}
+	}
+
+	/// Sets whether this codec runs on the server side of the connection,
+	/// which controls whether incoming frames are expected to be masked
+	/// and whether outgoing frames must be.
+	pub fn server(mut self, is_server: bool) -> Self {
+		self.is_server = is_server;
+		self
+	}
+
+	/// Caps the size of a message reassembled from continuation frames.
+	pub fn max_size(mut self, max_size: usize) -> Self {
+		self.max_size = max_size;
+		self
+	}
+
+	fn decode_frame(&mut self, src: &mut BytesMut) -> WebSocketResult<Option<Frame>> {
+		if src.len() < 2 {
+			return Ok(None);
+		}
+
+		let first_byte = src[0];
+		let second_byte = src[1];
+
+		let fin = first_byte & 0x80 != 0;
+		let reserved = [first_byte & 0x40 != 0, first_byte & 0x20 != 0, first_byte & 0x10 != 0];
+		let opcode = try!(opcode_from_nibble(first_byte & 0x0F));
+
+		let masked = second_byte & 0x80 != 0;
+		if masked != self.is_server {
+			return Err(WebSocketError::ProtocolError(
+				if self.is_server {
+					"Frames from a client must be masked".to_string()
+				} else {
+					"Frames from a server must not be masked".to_string()
+				}
+			));
+		}
+
+		let mut offset = 2;
+		let payload_len = match second_byte & 0x7F {
+			126 => {
+				if src.len() < offset + 2 { return Ok(None); }
+				let len = BigEndian::read_u16(&src[offset..offset + 2]) as usize;
+				offset += 2;
+				len
+			}
+			127 => {
+				if src.len() < offset + 8 { return Ok(None); }
+				let len = BigEndian::read_u64(&src[offset..offset + 8]) as usize;
+				offset += 8;
+				len
+			}
+			small => small as usize,
+		};
+
+		if payload_len > self.max_size {
+			return Err(WebSocketError::MessageTooBig { limit: self.max_size });
+		}
+
+		// RFC 6455 §5.5: all control frames must have a payload length of
+		// 125 bytes or less and must not be fragmented.
+		if let Opcode::Ping | Opcode::Pong | Opcode::Close = opcode {
+			if payload_len > 125 {
+				return Err(WebSocketError::ProtocolError(
+					"Control frame payload must not exceed 125 bytes".to_string()
+				));
+			}
+		}
+
+		let mask_key = if masked {
+			if src.len() < offset + 4 { return Ok(None); }
+			let key = [src[offset], src[offset + 1], src[offset + 2], src[offset + 3]];
+			offset += 4;
+			Some(key)
+		} else {
+			None
+		};
+
+		let frame_len = match offset.checked_add(payload_len) {
+			Some(len) => len,
+			None => return Err(WebSocketError::ProtocolError(
+				"Frame length overflows a machine word".to_string()
+			)),
+		};
+		if src.len() < frame_len {
+			return Ok(None);
+		}
+
+		let frame_bytes = src.split_to(frame_len);
+		let mut payload = frame_bytes[offset..].to_vec();
+
+		if let Some(key) = mask_key {
+			for (i, byte) in payload.iter_mut().enumerate() {
+				*byte ^= key[i % 4];
+			}
+		}
+
+		Ok(Some(Frame { fin: fin, reserved: reserved, opcode: opcode, payload: payload }))
+	}
+}
+
+impl Decoder for MessageCodec {
+	type Item = Message<'static>;
+	type Error = WebSocketError;
+
+	fn decode(&mut self, src: &mut BytesMut) -> WebSocketResult<Option<Self::Item>> {
+		loop {
+			let frame = match try!(self.decode_frame(src)) {
+				Some(frame) => frame,
+				None => return Ok(None),
+			};
+
+			// Control frames may be interleaved between the fragments of a
+			// data message and must never be fragmented themselves; deliver
+			// them standalone instead of folding them into `self.fragments`.
+			match frame.opcode() {
+				Opcode::Ping | Opcode::Pong | Opcode::Close => {
+					if !frame.is_last() {
+						return Err(WebSocketError::ProtocolError(
+							"Control frames must not be fragmented".to_string()
+						));
+					}
+					return Message::from_dataframes_with_limit(vec![frame], self.max_size).map(Some);
+				}
+				_ => {}
+			}
+
+			if self.fragments.is_empty() && frame.opcode() == Opcode::Text {
+				self.text_validator = Some(Utf8Validator::new());
+			}
+			if let Some(ref mut validator) = self.text_validator {
+				try!(validator.feed(frame.payload()));
+			}
+
+			let is_last = frame.is_last();
+			self.fragments.push(frame);
+
+			if is_last {
+				if let Some(validator) = self.text_validator.take() {
+					try!(validator.finish());
+				}
+				let fragments = mem::replace(&mut self.fragments, Vec::new());
+				return Message::from_dataframes_with_limit(fragments, self.max_size).map(Some);
+			}
+		}
+	}
+}
+
+impl<'m> Encoder<Message<'m>> for MessageCodec {
+	type Error = WebSocketError;
+
+	fn encode(&mut self, item: Message<'m>, dst: &mut BytesMut) -> WebSocketResult<()> {
+		let mask = !self.is_server;
+		for frame in item.dataframes() {
+			try!(write_frame(&frame, mask, dst));
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn round_trips_a_single_frame_text_message() {
+		let mut client = MessageCodec::new();
+		let mut server = MessageCodec::new().server(true);
+		let mut buf = BytesMut::new();
+
+		let sent = Message::text("hello");
+		client.encode(sent.clone(), &mut buf).unwrap();
+
+		let received = server.decode(&mut buf).unwrap().expect("a full frame was buffered");
+		assert_eq!(received.opcode, sent.opcode);
+		assert_eq!(received.payload(), sent.payload());
+	}
+
+	#[test]
+	fn round_trips_a_fragmented_message() {
+		let mut client = MessageCodec::new();
+		let mut server = MessageCodec::new().server(true);
+		let mut buf = BytesMut::new();
+
+		let sent = Message::text("hello, world").max_frame_size(4);
+		client.encode(sent.clone(), &mut buf).unwrap();
+
+		let received = server.decode(&mut buf).unwrap().expect("a full message was buffered");
+		assert_eq!(received.opcode, Opcode::Text);
+		assert_eq!(received.payload(), sent.payload());
+	}
+
+	#[test]
+	fn delivers_a_control_frame_interleaved_between_fragments_standalone() {
+		let mut client = MessageCodec::new();
+		let mut server = MessageCodec::new().server(true);
+		let mut buf = BytesMut::new();
+
+		let source = Message::text("hello, world").max_frame_size(4);
+		let data_frames = source.dataframes().collect::<Vec<_>>();
+		write_frame(&data_frames[0], true, &mut buf).unwrap();
+		client.encode(Message::ping(&b"pong-me"[..]), &mut buf).unwrap();
+		for frame in &data_frames[1..] {
+			write_frame(frame, true, &mut buf).unwrap();
+		}
+
+		let ping = server.decode(&mut buf).unwrap().expect("the ping was buffered");
+		assert_eq!(ping.opcode, Opcode::Ping);
+
+		let text = server.decode(&mut buf).unwrap().expect("the full message was buffered");
+		assert_eq!(text.opcode, Opcode::Text);
+		assert_eq!(text.payload(), Message::text("hello, world").payload());
+	}
+
+	#[test]
+	fn rejects_a_control_frame_payload_over_125_bytes() {
+		let mut server = MessageCodec::new().server(true);
+		let mut buf = BytesMut::new();
+
+		let frame = Frame { fin: true, reserved: [false; 3], opcode: Opcode::Ping, payload: vec![0u8; 126] };
+		write_frame(&frame, true, &mut buf).unwrap();
+
+		assert!(server.decode(&mut buf).is_err());
+	}
+
+	#[test]
+	fn rejects_invalid_utf8_in_a_text_frame_as_soon_as_it_arrives() {
+		let mut server = MessageCodec::new().server(true);
+		let mut buf = BytesMut::new();
+
+		let frame = Frame { fin: true, reserved: [false; 3], opcode: Opcode::Text, payload: vec![0xFF, 0xFE] };
+		write_frame(&frame, true, &mut buf).unwrap();
+
+		assert!(server.decode(&mut buf).is_err());
+	}
+}
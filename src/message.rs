@@ -3,34 +3,209 @@
 use std::io;
 use std::io::Result as IoResult;
 use std::io::Write;
-use std::iter::{Take, Repeat, repeat};
+use std::str;
+use std::sync::OnceLock;
+use std::vec::IntoIter;
 use result::{WebSocketResult, WebSocketError};
 use dataframe::{DataFrame, Opcode, DataFrameRef};
-use byteorder::{WriteBytesExt, ReadBytesExt, BigEndian};
-use ws::util::message::bytes_to_string;
+use byteorder::{ReadBytesExt, BigEndian};
 use ws;
 
 use std::borrow::Cow;
 
 const FALSE_RESERVED_BITS: &'static [bool; 3] = &[false; 3];
 
+/// Default cap on the total size of a message reassembled from data frames,
+/// used by `from_dataframes` to guard against a peer sending an endless
+/// stream of fragments. Matches the default used by other WebSocket
+/// implementations such as actix's codec.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Status code sent in a WebSocket Close frame, as defined by
+/// [RFC 6455 §7.4.1](https://tools.ietf.org/html/rfc6455#section-7.4.1).
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum CloseCode {
+	Normal,
+	GoingAway,
+	ProtocolError,
+	UnsupportedData,
+	InvalidPayload,
+	PolicyViolation,
+	MessageTooBig,
+	InternalError,
+	/// Any other code not specifically handled above, including codes
+	/// reserved for use by extensions, frameworks and applications.
+	Other(u16),
+}
+
+impl CloseCode {
+	/// Parses a close status code, rejecting codes the RFC reserves as
+	/// invalid to send over the wire (0-999, 1004, 1005, 1006 and 1015) as
+	/// well as the 1016-2999 range, which Autobahn's test suite requires
+	/// rejecting since it is reserved for future use by the standard and
+	/// not yet assigned to frameworks or applications.
+	pub fn try_from(code: u16) -> WebSocketResult<CloseCode> {
+		match code {
+			1000 => Ok(CloseCode::Normal),
+			1001 => Ok(CloseCode::GoingAway),
+			1002 => Ok(CloseCode::ProtocolError),
+			1003 => Ok(CloseCode::UnsupportedData),
+			1007 => Ok(CloseCode::InvalidPayload),
+			1008 => Ok(CloseCode::PolicyViolation),
+			1009 => Ok(CloseCode::MessageTooBig),
+			1011 => Ok(CloseCode::InternalError),
+			0..=999 | 1004 | 1005 | 1006 | 1015 | 1016..=2999 => Err(WebSocketError::ProtocolError(
+				format!("Invalid close status code: {}", code)
+			)),
+			other => Ok(CloseCode::Other(other)),
+		}
+	}
+
+	pub fn to_be_bytes(self) -> [u8; 2] {
+		let code: u16 = self.into();
+		[(code >> 8) as u8, code as u8]
+	}
+}
+
+impl From<CloseCode> for u16 {
+	fn from(code: CloseCode) -> u16 {
+		match code {
+			CloseCode::Normal => 1000,
+			CloseCode::GoingAway => 1001,
+			CloseCode::ProtocolError => 1002,
+			CloseCode::UnsupportedData => 1003,
+			CloseCode::InvalidPayload => 1007,
+			CloseCode::PolicyViolation => 1008,
+			CloseCode::MessageTooBig => 1009,
+			CloseCode::InternalError => 1011,
+			CloseCode::Other(code) => code,
+		}
+	}
+}
+
+/// A Text payload or Close reason was not well-formed UTF-8.
+#[derive(Debug)]
+pub struct InvalidUtf8;
+
+impl From<InvalidUtf8> for WebSocketError {
+	fn from(_: InvalidUtf8) -> WebSocketError {
+		WebSocketError::InvalidUtf8
+	}
+}
+
+fn validate_utf8(data: &[u8]) -> Result<String, InvalidUtf8> {
+	str::from_utf8(data).map(|s| s.to_string()).map_err(|_| InvalidUtf8)
+}
+
+/// Incrementally validates UTF-8 across a stream of fragments, as required
+/// to reject invalid text as soon as a fragment proves it is malformed
+/// instead of waiting for the whole message to be reassembled. A multibyte
+/// sequence split across two fragments is buffered and checked once its
+/// continuation bytes arrive.
+#[derive(Default)]
+pub struct Utf8Validator {
+	incomplete: Vec<u8>,
+}
+
+impl Utf8Validator {
+	pub fn new() -> Self {
+		Utf8Validator { incomplete: Vec::new() }
+	}
+
+	/// Validates `chunk` as the next piece of the stream, buffering a
+	/// trailing incomplete multibyte sequence for the following call.
+	pub fn feed(&mut self, chunk: &[u8]) -> Result<(), InvalidUtf8> {
+		let mut buf = Vec::with_capacity(self.incomplete.len() + chunk.len());
+		buf.extend_from_slice(&self.incomplete);
+		buf.extend_from_slice(chunk);
+
+		match str::from_utf8(&buf) {
+			Ok(_) => {
+				self.incomplete.clear();
+				Ok(())
+			}
+			Err(err) => {
+				let valid_up_to = err.valid_up_to();
+				match err.error_len() {
+					// The bytes after `valid_up_to` are the start of a
+					// multibyte sequence that simply hasn't arrived in
+					// full yet; a well-formed sequence is at most 4 bytes.
+					None if buf.len() - valid_up_to < 4 => {
+						self.incomplete = buf[valid_up_to..].to_vec();
+						Ok(())
+					}
+					_ => Err(InvalidUtf8),
+				}
+			}
+		}
+	}
+
+	/// Call once the stream has ended; fails if a multibyte sequence was
+	/// left dangling without its continuation bytes.
+	pub fn finish(self) -> Result<(), InvalidUtf8> {
+		if self.incomplete.is_empty() {
+			Ok(())
+		} else {
+			Err(InvalidUtf8)
+		}
+	}
+}
+
 /// Represents a WebSocket message.
-#[derive(PartialEq, Clone, Debug)]
+///
+/// The payload is kept as an ordered list of chunks rather than a single
+/// contiguous buffer, so that a message assembled from several borrowed
+/// slices (e.g. a header, a body and a trailer) never needs to be
+/// concatenated just to be sent. `write_payload` writes each chunk in turn;
+/// `payload()` only allocates a contiguous buffer if more than one chunk is
+/// present.
+#[derive(Clone, Debug)]
 pub struct Message<'a> {
 	pub opcode: Opcode,
-	pub cd_status_code: Option<u16>,
-	pub payload: Cow<'a, [u8]>,
+	pub cd_status_code: Option<CloseCode>,
+	pub payload: Vec<Cow<'a, [u8]>>,
+	is_last: bool,
+	max_frame_size: Option<usize>,
+	/// Lazily-populated concatenation of `payload`, used to satisfy
+	/// `DataFrame::payload()`'s `&[u8]` return type without reallocating on
+	/// every call. `OnceLock` rather than `OnceCell` so `Message` stays
+	/// `Sync`, since consumers commonly share a `&Message` across threads.
+	contiguous_payload: OnceLock<Vec<u8>>,
+}
+
+impl<'a> PartialEq for Message<'a> {
+	fn eq(&self, other: &Self) -> bool {
+		self.opcode == other.opcode
+			&& self.cd_status_code == other.cd_status_code
+			&& self.payload == other.payload
+			&& self.is_last == other.is_last
+			&& self.max_frame_size == other.max_frame_size
+	}
 }
 
 impl<'a> Message<'a> {
-	fn new(code: Opcode, status: Option<u16>, payload: Cow<'a, [u8]>) -> Self {
+	fn new(code: Opcode, status: Option<CloseCode>, payload: Cow<'a, [u8]>) -> Self {
 		Message {
 			opcode: code,
 			cd_status_code: status,
-			payload: payload,
+			payload: vec![payload],
+			is_last: true,
+			max_frame_size: None,
+			contiguous_payload: OnceLock::new(),
 		}
 	}
 
+	/// Sets the maximum size (in bytes) of each frame this message is split
+	/// into when sent. `Text` and `Binary` messages larger than this
+	/// threshold are fragmented into a first frame carrying the real opcode
+	/// followed by `Continuation` frames, only the last of which sets FIN.
+	/// Control frames (`Ping`/`Pong`/`Close`) are never fragmented. A
+	/// `max_frame_size` of 0 disables fragmentation.
+	pub fn max_frame_size(mut self, max_frame_size: usize) -> Self {
+		self.max_frame_size = Some(max_frame_size);
+		self
+	}
+
 	pub fn text<S>(data: S) -> Self
 	where S: Into<Cow<'a, str>> {
 		Message::new(Opcode::Text, None, match data.into() {
@@ -44,11 +219,24 @@ impl<'a> Message<'a> {
 		Message::new(Opcode::Binary, None, data.into())
 	}
 
+	/// Builds a binary message from several chunks that are written out in
+	/// order without being concatenated first.
+	pub fn binary_chunks(chunks: Vec<Cow<'a, [u8]>>) -> Self {
+		Message {
+			opcode: Opcode::Binary,
+			cd_status_code: None,
+			payload: chunks,
+			is_last: true,
+			max_frame_size: None,
+			contiguous_payload: OnceLock::new(),
+		}
+	}
+
 	pub fn close() -> Self {
 		Message::new(Opcode::Close, None, Cow::Borrowed(&[0 as u8; 0]))
 	}
 
-	pub fn close_because<S>(code: u16, reason: S) -> Self
+	pub fn close_because<S>(code: CloseCode, reason: S) -> Self
 	where S: Into<Cow<'a, str>> {
 		Message::new(Opcode::Close, Some(code), match reason.into() {
 			Cow::Owned(msg) => Cow::Owned(msg.into_bytes()),
@@ -65,53 +253,36 @@ impl<'a> Message<'a> {
 	where P: Into<Cow<'a, [u8]>> {
 		Message::new(Opcode::Pong, None, data.into())
 	}
-}
-
-impl<'a> ws::dataframe::DataFrame for Message<'a> {
-    fn is_last(&self) -> bool {
-        true
-    }
-
-    fn opcode(&self) -> Opcode {
-        self.opcode
-    }
-
-    fn reserved<'b>(&'b self) -> &'b [bool; 3] {
-		FALSE_RESERVED_BITS
-    }
-
-	fn payload<'b>(&'b self) -> &'b [u8] {
-		unimplemented!();
-	}
 
-    fn write_payload<W>(&self, socket: &mut W) -> IoResult<()>
-    where W: Write {
-		if let Some(reason) = self.cd_status_code {
-			try!(socket.write_u16::<BigEndian>(reason));
+	/// Returns the payload as a single contiguous slice, allocating a fresh
+	/// buffer only when the payload is made up of more than one chunk.
+	pub fn payload(&self) -> Cow<[u8]> {
+		match self.payload.len() {
+			1 => Cow::Borrowed(&*self.payload[0]),
+			_ => Cow::Owned(self.payload.iter().flat_map(|chunk| chunk.iter().cloned()).collect()),
 		}
-		socket.write_all(&*self.payload)
-    }
-}
-
-impl<'a, 'b> ws::Message<'b, Message<'b>> for Message<'a> {
-
-	type DataFrameIterator = Take<Repeat<Message<'b>>>;
-
-	fn dataframes(&'b self) -> Self::DataFrameIterator {
-		repeat(self.clone()).take(1)
-    }
+	}
 
-	/// Attempt to form a message from a series of data frames
-	fn from_dataframes<D>(frames: Vec<D>) -> WebSocketResult<Self>
-    where D: ws::dataframe::DataFrame {
+	/// Attempt to form a message from a series of data frames, aborting with
+	/// `WebSocketError::MessageTooBig` (which the connection layer should
+	/// translate into a `CloseCode::MessageTooBig` close) as soon as the
+	/// reassembled payload would exceed `max_size` bytes.
+	///
+	/// `Text` payloads are validated as UTF-8 once the full message has been
+	/// reassembled, in `from_opcode_and_data`. A caller that wants to reject
+	/// invalid UTF-8 as soon as the offending fragment arrives, instead of
+	/// waiting for the whole message, should feed fragments through a
+	/// `Utf8Validator` of its own as they are received (see `MessageCodec`).
+	pub fn from_dataframes_with_limit<D>(frames: Vec<D>, max_size: usize) -> WebSocketResult<Self>
+	where D: ws::dataframe::DataFrame {
 		let opcode = try!(frames.first().ok_or(WebSocketError::ProtocolError(
 			"No dataframes provided".to_string()
 		)).map(|d| d.opcode()));
 
 		let mut data = Vec::new();
 
-		for dataframe in frames.iter() {
-			if dataframe.opcode() != Opcode::Continuation {
+		for (i, dataframe) in frames.iter().enumerate() {
+			if i > 0 && dataframe.opcode() != Opcode::Continuation {
 				return Err(WebSocketError::ProtocolError(
 					"Unexpected non-continuation data frame".to_string()
 				));
@@ -121,17 +292,29 @@ impl<'a, 'b> ws::Message<'b, Message<'b>> for Message<'a> {
 					"Unsupported reserved bits received".to_string()
 				));
 			}
+			if data.len() + dataframe.payload().len() > max_size {
+				return Err(WebSocketError::MessageTooBig { limit: max_size });
+			}
 			data.extend(dataframe.payload().iter().cloned());
 		}
 
+		Message::from_opcode_and_data(opcode, data)
+	}
+
+	fn from_opcode_and_data(opcode: Opcode, data: Vec<u8>) -> WebSocketResult<Self> {
 		Ok(match opcode {
-			Opcode::Text => Message::text(try!(bytes_to_string(&data[..]))),
+			Opcode::Text => Message::text(try!(validate_utf8(&data[..]))),
 			Opcode::Binary => Message::binary(data),
 			Opcode::Close => {
-				if data.len() > 0 {
+				if data.len() == 1 {
+					return Err(WebSocketError::ProtocolError(
+						"Close frame status code must be exactly 2 bytes".to_string()
+					));
+				} else if data.len() > 0 {
 					let status_code = try!((&data[..]).read_u16::<BigEndian>());
-					let reason = try!(bytes_to_string(&data[2..]));
-					Message::close_because(status_code, reason)
+					let code = try!(CloseCode::try_from(status_code));
+					let reason = try!(validate_utf8(&data[2..]));
+					Message::close_because(code, reason)
 				} else {
 					Message::close()
 				}
@@ -144,3 +327,167 @@ impl<'a, 'b> ws::Message<'b, Message<'b>> for Message<'a> {
 		})
 	}
 }
+
+impl<'a> ws::dataframe::DataFrame for Message<'a> {
+    fn is_last(&self) -> bool {
+        self.is_last
+    }
+
+    fn opcode(&self) -> Opcode {
+        self.opcode
+    }
+
+    fn reserved<'b>(&'b self) -> &'b [bool; 3] {
+		FALSE_RESERVED_BITS
+    }
+
+	fn payload<'b>(&'b self) -> &'b [u8] {
+		match self.payload.len() {
+			1 => &*self.payload[0],
+			_ => self.contiguous_payload.get_or_init(|| self.payload().into_owned()),
+		}
+	}
+
+    fn write_payload<W>(&self, socket: &mut W) -> IoResult<()>
+    where W: Write {
+		if let Some(code) = self.cd_status_code {
+			try!(socket.write_all(&code.to_be_bytes()));
+		}
+		for chunk in &self.payload {
+			try!(socket.write_all(&*chunk));
+		}
+		Ok(())
+    }
+}
+
+/// Splits a `Text`/`Binary` payload into a first frame carrying `opcode`
+/// followed by `Continuation` frames, none larger than `max_frame_size`,
+/// with only the last frame's FIN bit set. A `max_frame_size` of 0 yields a
+/// single unfragmented frame.
+fn fragment<'b>(opcode: Opcode, payload: &[Cow<[u8]>], max_frame_size: usize) -> Vec<Message<'b>> {
+	let data: Vec<u8> = payload.iter().flat_map(|chunk| chunk.iter().cloned()).collect();
+
+	if max_frame_size == 0 || data.len() <= max_frame_size {
+		return vec![Message {
+			opcode: opcode,
+			cd_status_code: None,
+			payload: vec![Cow::Owned(data)],
+			is_last: true,
+			max_frame_size: None,
+			contiguous_payload: OnceLock::new(),
+		}];
+	}
+
+	let mut frames: Vec<Message<'b>> = data.chunks(max_frame_size).enumerate().map(|(i, chunk)| {
+		Message {
+			opcode: if i == 0 { opcode } else { Opcode::Continuation },
+			cd_status_code: None,
+			payload: vec![Cow::Owned(chunk.to_vec())],
+			is_last: false,
+			max_frame_size: None,
+			contiguous_payload: OnceLock::new(),
+		}
+	}).collect();
+
+	if let Some(last) = frames.last_mut() {
+		last.is_last = true;
+	}
+
+	frames
+}
+
+impl<'a, 'b> ws::Message<'b, Message<'b>> for Message<'a> {
+
+	type DataFrameIterator = IntoIter<Message<'b>>;
+
+	fn dataframes(&'b self) -> Self::DataFrameIterator {
+		match (self.opcode, self.max_frame_size) {
+			(Opcode::Text, Some(max_frame_size)) | (Opcode::Binary, Some(max_frame_size)) => {
+				fragment(self.opcode, &self.payload, max_frame_size)
+			}
+			_ => vec![self.clone()],
+		}.into_iter()
+    }
+
+	/// Attempt to form a message from a series of data frames, capping the
+	/// reassembled payload at `DEFAULT_MAX_MESSAGE_SIZE` bytes.
+	fn from_dataframes<D>(frames: Vec<D>) -> WebSocketResult<Self>
+    where D: ws::dataframe::DataFrame {
+		Message::from_dataframes_with_limit(frames, DEFAULT_MAX_MESSAGE_SIZE)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use ws::Message as _;
+
+	#[test]
+	fn accepts_named_close_codes() {
+		assert_eq!(CloseCode::try_from(1000).unwrap(), CloseCode::Normal);
+		assert_eq!(CloseCode::try_from(1009).unwrap(), CloseCode::MessageTooBig);
+		assert_eq!(CloseCode::try_from(1011).unwrap(), CloseCode::InternalError);
+	}
+
+	#[test]
+	fn rejects_reserved_close_codes() {
+		for code in [0, 999, 1004, 1005, 1006, 1015, 1016, 2000, 2999] {
+			assert!(CloseCode::try_from(code).is_err(), "expected {} to be rejected", code);
+		}
+	}
+
+	#[test]
+	fn accepts_codes_above_the_reserved_range_as_other() {
+		assert_eq!(CloseCode::try_from(3000).unwrap(), CloseCode::Other(3000));
+		assert_eq!(CloseCode::try_from(4999).unwrap(), CloseCode::Other(4999));
+	}
+
+	#[test]
+	fn rejects_a_close_frame_with_a_single_byte_payload() {
+		assert!(Message::from_opcode_and_data(Opcode::Close, vec![0]).is_err());
+	}
+
+	#[test]
+	fn rejects_a_message_exceeding_the_size_limit() {
+		let source = Message::binary(vec![0u8; 10]);
+		let frames = source.dataframes().collect::<Vec<_>>();
+		match Message::from_dataframes_with_limit(frames, 5) {
+			Err(WebSocketError::MessageTooBig { limit }) => assert_eq!(limit, 5),
+			other => panic!("expected MessageTooBig, got {:?}", other),
+		}
+	}
+
+	#[test]
+	fn reassembles_a_fragmented_message() {
+		let original = Message::text("the quick brown fox").max_frame_size(4);
+		let frames = original.dataframes().collect::<Vec<_>>();
+		assert!(frames.len() > 1);
+
+		let reassembled = Message::from_dataframes_with_limit(frames, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+		assert_eq!(reassembled.opcode, Opcode::Text);
+		assert_eq!(reassembled.payload(), original.payload());
+	}
+
+	#[test]
+	fn validates_a_multibyte_sequence_split_across_fragments() {
+		let bytes = "h\u{e9}llo".as_bytes();
+		let mut validator = Utf8Validator::new();
+		validator.feed(&bytes[..2]).unwrap();
+		validator.feed(&bytes[2..]).unwrap();
+		validator.finish().unwrap();
+	}
+
+	#[test]
+	fn rejects_an_incomplete_multibyte_sequence_at_the_end_of_the_stream() {
+		let bytes = "h\u{e9}llo".as_bytes();
+		let mut validator = Utf8Validator::new();
+		validator.feed(&bytes[..2]).unwrap();
+		assert!(validator.finish().is_err());
+	}
+
+	#[test]
+	fn rejects_invalid_utf8() {
+		let mut validator = Utf8Validator::new();
+		assert!(validator.feed(&[0xFF, 0xFE]).is_err());
+	}
+}
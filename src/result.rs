@@ -0,0 +1,58 @@
+//! Error and result types used throughout this crate.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+pub type WebSocketResult<T> = Result<T, WebSocketError>;
+
+/// Represents a WebSocket error.
+#[derive(Debug)]
+pub enum WebSocketError {
+	/// A protocol-level error, such as a malformed frame or an invalid
+	/// sequence of frames.
+	ProtocolError(String),
+	/// An I/O error arising from the underlying stream.
+	IoError(io::Error),
+	/// A reassembled message exceeded the configured size limit. The
+	/// connection layer should translate this into a
+	/// `CloseCode::MessageTooBig` (1009) close.
+	MessageTooBig {
+		/// The size limit, in bytes, that the message exceeded.
+		limit: usize,
+	},
+	/// A Text payload or Close reason was not well-formed UTF-8. The
+	/// connection layer should translate this into a
+	/// `CloseCode::InvalidPayload` (1007) close.
+	InvalidUtf8,
+}
+
+impl fmt::Display for WebSocketError {
+	fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			WebSocketError::ProtocolError(ref msg) => write!(fmt, "WebSocket protocol error: {}", msg),
+			WebSocketError::IoError(ref err) => write!(fmt, "WebSocket I/O error: {}", err),
+			WebSocketError::MessageTooBig { limit } => {
+				write!(fmt, "Message exceeds the maximum allowed size of {} bytes", limit)
+			}
+			WebSocketError::InvalidUtf8 => write!(fmt, "Payload is not valid UTF-8"),
+		}
+	}
+}
+
+impl Error for WebSocketError {
+	fn description(&self) -> &str {
+		match *self {
+			WebSocketError::ProtocolError(ref msg) => msg,
+			WebSocketError::IoError(ref err) => err.description(),
+			WebSocketError::MessageTooBig { .. } => "message too big",
+			WebSocketError::InvalidUtf8 => "payload is not valid UTF-8",
+		}
+	}
+}
+
+impl From<io::Error> for WebSocketError {
+	fn from(err: io::Error) -> WebSocketError {
+		WebSocketError::IoError(err)
+	}
+}